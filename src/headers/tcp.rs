@@ -1,3 +1,6 @@
+use std::cmp::Ordering;
+use std::ops::{Add, AddAssign, Sub};
+
 use crate::AsBeBytes;
 use super::{Header, TransportHeader, PacketData, Protocol, ParseError, PseudoHeader};
 
@@ -5,13 +8,88 @@ use super::{Header, TransportHeader, PacketData, Protocol, ParseError, PseudoHea
 pub struct TcpHeader {
     #[get] #[set] src_port: u16,
     #[get] #[set] dst_port: u16,
-    #[get] #[set] flags: u8,
+    #[get] #[set] seq_num: SeqNumber,
+    #[get] #[set] ack_num: SeqNumber,
+    #[get] #[set] flags: u16,
     #[get] #[set] window: u16,
+    #[get] #[set] urg_ptr: u16,
+    #[get] options: Vec<TcpOption>,
+    #[get] reserved: u8,
     pseudo_header: Option<PseudoHeader>,
     #[get] pseudo_header_set: bool
 }
 
+/// A single TCP option, as carried after the fixed 20-byte header (RFC 793 / RFC 7323).
+/// Kinds this crate doesn't model explicitly (e.g. SACK blocks, kind 5) round-trip through
+/// `Unknown` instead of failing to parse, so a captured segment carrying them can still be
+/// mutated and re-emitted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    EndOfOptions,
+    Nop,
+    MaxSegmentSize(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Timestamp { tsval: u32, tsecr: u32 },
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+const OPT_KIND_END: u8 = 0;
+const OPT_KIND_NOP: u8 = 1;
+const OPT_KIND_MSS: u8 = 2;
+const OPT_KIND_WINDOW_SCALE: u8 = 3;
+const OPT_KIND_SACK_PERMITTED: u8 = 4;
+const OPT_KIND_TIMESTAMP: u8 = 8;
+
+/// The 4-bit data-offset field can address a header of at most 15 * 4 = 60 bytes, so the
+/// padded options region can be at most 60 - 20 = 40 bytes.
+const MAX_OPTIONS_LEN: usize = 40;
+
+/// A TCP sequence/acknowledgment number. Comparisons and arithmetic wrap around the
+/// 32-bit space the way RFC 793 section 3.3 defines "SEG.SEQ < SND.NXT" etc: by the sign of the
+/// difference modulo 2^32, not by the raw integer value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeqNumber(pub u32);
+
+impl SeqNumber {
+    pub fn new(value: u32) -> Self {
+        SeqNumber(value)
+    }
+}
+
+impl Add<u32> for SeqNumber {
+    type Output = SeqNumber;
+
+    fn add(self, rhs: u32) -> SeqNumber {
+        SeqNumber(self.0.wrapping_add(rhs))
+    }
+}
+
+impl AddAssign<u32> for SeqNumber {
+    fn add_assign(&mut self, rhs: u32) {
+        self.0 = self.0.wrapping_add(rhs);
+    }
+}
+
+impl Sub<u32> for SeqNumber {
+    type Output = SeqNumber;
+
+    fn sub(self, rhs: u32) -> SeqNumber {
+        SeqNumber(self.0.wrapping_sub(rhs))
+    }
+}
+
+impl PartialOrd for SeqNumber {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self.0.wrapping_sub(other.0) as i32).partial_cmp(&0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TcpFlags {
+    Ns,
+    Cwr,
+    Ece,
     Urg,
     Ack,
     Psh,
@@ -20,100 +98,184 @@ pub enum TcpFlags {
     Fin,
 }
 
+// NS lives in the low bit of byte 12 (the data-offset/reserved byte); the rest pack into
+// byte 13 in wire order (CWR ECE URG ACK PSH RST SYN FIN), so `flags` is a u16 with NS at
+// bit 8 and the classic six (plus CWR/ECE) at bits 0-7.
+const FLAG_NS: u16 = 0b1_0000_0000;
+const FLAG_CWR: u16 = 0b0_1000_0000;
+const FLAG_ECE: u16 = 0b0_0100_0000;
+const FLAG_URG: u16 = 0b0_0010_0000;
+const FLAG_ACK: u16 = 0b0_0001_0000;
+const FLAG_PSH: u16 = 0b0_0000_1000;
+const FLAG_RST: u16 = 0b0_0000_0100;
+const FLAG_SYN: u16 = 0b0_0000_0010;
+const FLAG_FIN: u16 = 0b0_0000_0001;
+
+fn flag_mask(f: TcpFlags) -> u16 {
+    match f {
+        TcpFlags::Ns => FLAG_NS,
+        TcpFlags::Cwr => FLAG_CWR,
+        TcpFlags::Ece => FLAG_ECE,
+        TcpFlags::Urg => FLAG_URG,
+        TcpFlags::Ack => FLAG_ACK,
+        TcpFlags::Psh => FLAG_PSH,
+        TcpFlags::Rst => FLAG_RST,
+        TcpFlags::Syn => FLAG_SYN,
+        TcpFlags::Fin => FLAG_FIN,
+    }
+}
+
 impl TcpHeader {
     pub fn new(src_port: u16, dst_port: u16) -> Self {
         TcpHeader {
             src_port: src_port,
             dst_port: dst_port,
+            seq_num: SeqNumber::new(0),
+            ack_num: SeqNumber::new(0),
             window: 0xffff,
             flags: 0,
+            urg_ptr: 0,
+            options: Vec::new(),
+            reserved: 0,
             pseudo_header: None,
             pseudo_header_set: false
         }
     }
 
+    pub fn add_option(&mut self, option: TcpOption) {
+        self.options.push(option);
+    }
+
     pub fn set_flag(&mut self, f: TcpFlags) {
-        match f {
-            TcpFlags::Urg => self.flags = self.flags | 0b00100000,
-            TcpFlags::Ack => self.flags = self.flags | 0b00010000,
-            TcpFlags::Psh => self.flags = self.flags | 0b00001000,
-            TcpFlags::Rst => self.flags = self.flags | 0b00000100,
-            TcpFlags::Syn => self.flags = self.flags | 0b00000010,
-            TcpFlags::Fin => self.flags = self.flags | 0b00000001,
-        }
+        self.flags |= flag_mask(f);
     }
-}
 
-impl TransportHeader for TcpHeader {
-    fn set_pseudo_header(&mut self, src_ip: [u8; 4], dst_ip: [u8; 4], data_len: u16) {
-        if data_len > (0xffff - 20) {
-            panic!("too much data");
-        }
-        self.pseudo_header = Some(PseudoHeader {
-            src_ip,
-            dst_ip,
-            protocol: 6, // 6 = tcp
-            data_len: (data_len + 20) as u16,
-        });
-        self.pseudo_header_set = true
+    pub fn clear_flag(&mut self, f: TcpFlags) {
+        self.flags &= !flag_mask(f);
     }
-}
 
-impl Header for TcpHeader {
-    fn make(self) -> PacketData {
+    pub fn get_flag(&self, f: TcpFlags) -> bool {
+        self.flags & flag_mask(f) != 0
+    }
+
+    /// Builds the segment and folds `payload` into the checksum, as required by RFC 793
+    /// since the checksum covers the pseudo-header, the TCP header itself and the data.
+    pub fn make_with_payload(self, payload: &[u8]) -> PacketData {
         let src_p = self.src_port.split_to_bytes();
         let dst_p = self.dst_port.split_to_bytes();
+        let seq_bytes = self.seq_num.0.split_to_bytes();
+        let ack_bytes = self.ack_num.0.split_to_bytes();
         let window_bytes = self.window.split_to_bytes();
+        let urg_ptr_bytes = self.urg_ptr.split_to_bytes();
+        let options_bytes = encode_options(&self.options);
+        let data_offset = ((20 + options_bytes.len()) / 4) as u8;
         let mut packet = vec![
             src_p[0],
             src_p[1],
             dst_p[0],
             dst_p[1],
-            0,
-            0,
-            0,
-            0, // Seq num
-            0,
-            0,
-            0,
-            0, // Ack num
-            0, // Offset + 4 of the reserved bits, the other 2 of the 6 total reserved bits are included at the start of the `flags` byte
-            self.flags,
+            seq_bytes[0],
+            seq_bytes[1],
+            seq_bytes[2],
+            seq_bytes[3], // Seq num
+            ack_bytes[0],
+            ack_bytes[1],
+            ack_bytes[2],
+            ack_bytes[3], // Ack num
+            (data_offset << 4) | ((self.reserved & 0b111) << 1) | ((self.flags >> 8) as u8 & 0b1), // Data offset, reserved bits, NS
+            (self.flags & 0xff) as u8, // CWR, ECE, URG, ACK, PSH, RST, SYN, FIN
             window_bytes[0],
             window_bytes[1],
             0,
-            0,
-            0,
-            0, // Urgent Pointer -> Should do this at some point
+            0, // Checksum, filled in below
+            urg_ptr_bytes[0],
+            urg_ptr_bytes[1],
         ];
+        packet.extend_from_slice(&options_bytes);
 
-        // calculate checksum
         if let None = self.pseudo_header {
             panic!("Please set the pseudo header data before calculating the checksum");
         }
         let pseudo_header = self.pseudo_header.unwrap();
+        // the pseudo-header's length field covers the TCP segment (header + data); the header
+        // length is derived fresh from the header actually being serialized, since `add_option`
+        // may have changed it after `set_pseudo_header` was called, while a payload whose
+        // length differs from the `data_len` passed there is rejected
+        if payload.len() != pseudo_header.data_len as usize {
+            panic!("payload length does not match the length passed to set_pseudo_header");
+        }
+        let segment_len = packet.len() as u32 + payload.len() as u32;
+        if segment_len > 0xffff {
+            panic!("too much data");
+        }
         let mut val = 0u32;
         val += ip_sum(pseudo_header.src_ip);
         val += ip_sum(pseudo_header.dst_ip);
         val += pseudo_header.protocol as u32; // add the value of the protocol field. Since this field is preceeded by an empty reserved byte, it maintains its value so we can just add 6 to the value as so
-        val += pseudo_header.data_len as u32; // header length (in bytes) : when there are no options+padding present, the header length is 20 bytes. this is a 16 bit field which is aligned on a boundary so we can just add this one aswell.
-        // checksum over data
+        val += segment_len; // header length (in bytes, including options) plus the payload length
+        // the checksum field itself is zeroed above, so folding `packet` in here covers the
+        // full header; folding `payload` in after covers the data segment as RFC 793 requires
+        val = sum_be_bytes(&packet, val);
+        val = sum_be_bytes(payload, val);
         let checksum = finalize_checksum(val).split_to_bytes();
 
         packet[16] = checksum[0];
         packet[17] = checksum[1];
         packet
     }
+}
+
+impl TransportHeader for TcpHeader {
+    fn set_pseudo_header(&mut self, src_ip: [u8; 4], dst_ip: [u8; 4], data_len: u16) {
+        // `data_len` is the payload length only; the header length is folded in fresh at
+        // `make_with_payload` time, since options (and so the header length) can still be
+        // added after this call, so the "too much data" bound is checked there instead, once
+        // the real header length is known.
+        self.pseudo_header = Some(PseudoHeader {
+            src_ip,
+            dst_ip,
+            protocol: 6, // 6 = tcp
+            data_len,
+        });
+        self.pseudo_header_set = true
+    }
+}
+
+impl Header for TcpHeader {
+    fn make(self) -> PacketData {
+        self.make_with_payload(&[])
+    }
 
     fn parse(raw_data: &[u8]) -> Result<Box<Self>, ParseError> {
         if raw_data.len() < Self::get_min_length().into() {
             return Err(ParseError::InvalidLength);
         }
+        let data_offset = (raw_data[12] >> 4) as usize;
+        let header_len = data_offset * 4;
+        if data_offset < 5 || raw_data.len() < header_len {
+            return Err(ParseError::InvalidLength);
+        }
+        let options = decode_options(&raw_data[20..header_len])?;
         Ok(Box::new(Self {
             src_port: ((raw_data[0] as u16) << 8) + raw_data[1] as u16,
             dst_port: ((raw_data[2] as u16) << 8) + raw_data[3] as u16,
-            flags: raw_data[13],
+            seq_num: SeqNumber::new(
+                ((raw_data[4] as u32) << 24)
+                    + ((raw_data[5] as u32) << 16)
+                    + ((raw_data[6] as u32) << 8)
+                    + raw_data[7] as u32,
+            ),
+            ack_num: SeqNumber::new(
+                ((raw_data[8] as u32) << 24)
+                    + ((raw_data[9] as u32) << 16)
+                    + ((raw_data[10] as u32) << 8)
+                    + raw_data[11] as u32,
+            ),
+            flags: (((raw_data[12] & 0b1) as u16) << 8) | raw_data[13] as u16,
             window: ((raw_data[14] as u16) << 8) + raw_data[15] as u16,
+            urg_ptr: ((raw_data[18] as u16) << 8) + raw_data[19] as u16,
+            options,
+            reserved: (raw_data[12] >> 1) & 0b111,
             pseudo_header: None,
             pseudo_header_set: false
         }))
@@ -124,7 +286,7 @@ impl Header for TcpHeader {
     }
 
     fn get_length(&self) -> u8 {
-        20
+        20 + encode_options(&self.options).len() as u8
     }
 
     fn get_min_length() -> u8 {
@@ -136,15 +298,259 @@ impl Header for TcpHeader {
     }
 }
 
+/// Serializes `options` using the kind/length/value encoding and pads the result to a
+/// 4-byte boundary with NOPs so the caller can derive the data-offset nibble from its length.
+fn encode_options(options: &[TcpOption]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for option in options {
+        match option {
+            TcpOption::EndOfOptions => bytes.push(OPT_KIND_END),
+            TcpOption::Nop => bytes.push(OPT_KIND_NOP),
+            TcpOption::MaxSegmentSize(mss) => {
+                bytes.push(OPT_KIND_MSS);
+                bytes.push(4);
+                bytes.extend_from_slice(&mss.split_to_bytes());
+            }
+            TcpOption::WindowScale(shift) => {
+                bytes.push(OPT_KIND_WINDOW_SCALE);
+                bytes.push(3);
+                bytes.push(*shift);
+            }
+            TcpOption::SackPermitted => {
+                bytes.push(OPT_KIND_SACK_PERMITTED);
+                bytes.push(2);
+            }
+            TcpOption::Timestamp { tsval, tsecr } => {
+                bytes.push(OPT_KIND_TIMESTAMP);
+                bytes.push(10);
+                bytes.extend_from_slice(&tsval.split_to_bytes());
+                bytes.extend_from_slice(&tsecr.split_to_bytes());
+            }
+            TcpOption::Unknown { kind, data } => {
+                bytes.push(*kind);
+                bytes.push(2 + data.len() as u8);
+                bytes.extend_from_slice(data);
+            }
+        }
+    }
+    while bytes.len() % 4 != 0 {
+        bytes.push(OPT_KIND_NOP);
+    }
+    if bytes.len() > MAX_OPTIONS_LEN {
+        panic!("TCP options do not fit in the 4-bit data offset field (max {} bytes padded, got {})", MAX_OPTIONS_LEN, bytes.len());
+    }
+    bytes
+}
+
+/// Walks the options region of a parsed header, rejecting any option whose declared
+/// length runs past the end of the region. A kind this crate doesn't model explicitly
+/// becomes `TcpOption::Unknown` instead, so it still round-trips through `encode_options`.
+fn decode_options(bytes: &[u8]) -> Result<Vec<TcpOption>, ParseError> {
+    let mut options = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let kind = bytes[i];
+        if kind == OPT_KIND_END {
+            options.push(TcpOption::EndOfOptions);
+            i += 1;
+            continue;
+        }
+        if kind == OPT_KIND_NOP {
+            options.push(TcpOption::Nop);
+            i += 1;
+            continue;
+        }
+        if i + 1 >= bytes.len() {
+            return Err(ParseError::InvalidLength);
+        }
+        let len = bytes[i + 1] as usize;
+        if len < 2 || i + len > bytes.len() {
+            return Err(ParseError::InvalidLength);
+        }
+        let value = &bytes[i + 2..i + len];
+        let option = match (kind, value.len()) {
+            (OPT_KIND_MSS, 2) => TcpOption::MaxSegmentSize(((value[0] as u16) << 8) + value[1] as u16),
+            (OPT_KIND_WINDOW_SCALE, 1) => TcpOption::WindowScale(value[0]),
+            (OPT_KIND_SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+            (OPT_KIND_TIMESTAMP, 8) => TcpOption::Timestamp {
+                tsval: ((value[0] as u32) << 24)
+                    + ((value[1] as u32) << 16)
+                    + ((value[2] as u32) << 8)
+                    + value[3] as u32,
+                tsecr: ((value[4] as u32) << 24)
+                    + ((value[5] as u32) << 16)
+                    + ((value[6] as u32) << 8)
+                    + value[7] as u32,
+            },
+            _ => TcpOption::Unknown { kind, data: value.to_vec() },
+        };
+        options.push(option);
+        i += len;
+    }
+    Ok(options)
+}
+
 #[inline(always)]
 fn ip_sum(octets: [u8; 4]) -> u32 {
     ((octets[0] as u32) << 8 | octets[1] as u32) + ((octets[2] as u32) << 8 | octets[3] as u32)
 }
 
+/// Folds successive 16-bit big-endian words of `bytes` into `acc`, padding a trailing odd
+/// byte with a zero as the one's-complement checksum algorithm (RFC 1071) requires.
+#[inline]
+fn sum_be_bytes(bytes: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = bytes.chunks_exact(2);
+    for word in &mut chunks {
+        acc += (word[0] as u32) << 8 | word[1] as u32;
+    }
+    if let [last] = *chunks.remainder() {
+        acc += (last as u32) << 8;
+    }
+    acc
+}
+
 #[inline]
 fn finalize_checksum(mut cs: u32) -> u16 {
     while cs >> 16 != 0 {
         cs = (cs >> 16) + (cs & 0xFFFF);
     }
+    // a folded sum of 0 must report as the one's-complement 0xFFFF, never 0x0000,
+    // since a checksum field of zero has special meaning on the wire
     !cs as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC_IP: [u8; 4] = [192, 168, 1, 1];
+    const DST_IP: [u8; 4] = [192, 168, 1, 2];
+
+    #[test]
+    fn seq_number_ordering_wraps_around_2_32() {
+        // naively 5 < 0xFFFFFFFE, but mod 2^32 the shorter forward step from
+        // 0xFFFFFFFE to 5 (wrapping through 0) makes 5 the later sequence number
+        assert!(SeqNumber::new(5) > SeqNumber::new(0xFFFFFFFE));
+
+        // 5 + 0xFFFFFFFB wraps back around to 0, which is less than 5
+        assert_eq!(SeqNumber::new(5) + 0xFFFFFFFBu32, SeqNumber::new(0));
+        assert!(SeqNumber::new(5) + 0xFFFFFFFBu32 < SeqNumber::new(5));
+    }
+
+    #[test]
+    fn checksum_matches_a_known_good_vector() {
+        let mut header = TcpHeader::new(1234, 80);
+        header.set_flag(TcpFlags::Syn);
+        header.set_pseudo_header(SRC_IP, DST_IP, 0);
+        let packet = header.make();
+        assert_eq!(&packet[16..18], &[0x27, 0x6d]);
+    }
+
+    #[test]
+    fn checksum_covers_the_payload() {
+        let mut header = TcpHeader::new(1234, 80);
+        header.set_flag(TcpFlags::Syn);
+        header.set_pseudo_header(SRC_IP, DST_IP, 2);
+        let packet = header.make_with_payload(b"hi");
+        assert_eq!(&packet[16..18], &[0xbf, 0x01]);
+    }
+
+    #[test]
+    #[should_panic(expected = "payload length does not match")]
+    fn checksum_rejects_a_payload_length_mismatch() {
+        let mut header = TcpHeader::new(1234, 80);
+        header.set_pseudo_header(SRC_IP, DST_IP, 2);
+        header.make_with_payload(b"too long");
+    }
+
+    #[test]
+    fn ns_cwr_ece_flags_round_trip_through_make_and_parse() {
+        let mut header = TcpHeader::new(1234, 80);
+        header.set_flag(TcpFlags::Ns);
+        header.set_flag(TcpFlags::Cwr);
+        header.set_flag(TcpFlags::Ece);
+        assert!(header.get_flag(TcpFlags::Ns));
+        assert!(header.get_flag(TcpFlags::Cwr));
+        assert!(header.get_flag(TcpFlags::Ece));
+
+        header.clear_flag(TcpFlags::Cwr);
+        assert!(!header.get_flag(TcpFlags::Cwr));
+
+        header.set_pseudo_header(SRC_IP, DST_IP, 0);
+        let packet = header.make();
+
+        let parsed = *TcpHeader::parse(&packet).unwrap();
+        assert!(parsed.get_flag(TcpFlags::Ns));
+        assert!(!parsed.get_flag(TcpFlags::Cwr));
+        assert!(parsed.get_flag(TcpFlags::Ece));
+    }
+
+    #[test]
+    fn parse_then_make_is_a_lossless_round_trip() {
+        let raw: [u8; 20] = [
+            0x04, 0x57, // src port
+            0x08, 0xae, // dst port
+            0x11, 0x22, 0x33, 0x44, // seq
+            0xaa, 0xbb, 0xcc, 0xdd, // ack
+            0x50, 0x12, // data offset (5) + flags (SYN, ACK)
+            0x20, 0x00, // window
+            0x00, 0x00, // checksum, recomputed on make
+            0x00, 0x00, // urgent pointer
+        ];
+        let mut header = *TcpHeader::parse(&raw).unwrap();
+        header.set_pseudo_header(SRC_IP, DST_IP, 0);
+        let packet = header.make();
+
+        assert_eq!(&packet[0..16], &raw[0..16]);
+        assert_eq!(&packet[18..20], &raw[18..20]);
+    }
+
+    #[test]
+    fn parse_then_make_round_trips_options() {
+        let raw: [u8; 24] = [
+            0x0b, 0xb8, // src port
+            0x01, 0xbb, // dst port
+            0x00, 0x00, 0x00, 0x01, // seq
+            0x00, 0x00, 0x00, 0x00, // ack
+            0x60, 0x10, // data offset (6) + flags (ACK)
+            0xff, 0xff, // window
+            0x00, 0x00, // checksum, recomputed on make
+            0x00, 0x00, // urgent pointer
+            0x02, 0x04, 0x05, 0xb4, // MSS = 1460
+        ];
+        let mut header = *TcpHeader::parse(&raw).unwrap();
+        assert_eq!(header.options()[0], TcpOption::MaxSegmentSize(1460));
+
+        header.set_pseudo_header(SRC_IP, DST_IP, 0);
+        let packet = header.make();
+
+        assert_eq!(&packet[0..16], &raw[0..16]);
+        assert_eq!(&packet[18..24], &raw[18..24]);
+    }
+
+    #[test]
+    fn parse_then_make_round_trips_an_unrecognized_option_like_sack() {
+        let raw: [u8; 28] = [
+            0x0b, 0xb8, // src port
+            0x01, 0xbb, // dst port
+            0x00, 0x00, 0x00, 0x01, // seq
+            0x00, 0x00, 0x00, 0x00, // ack
+            0x70, 0x10, // data offset (7) + flags (ACK)
+            0xff, 0xff, // window
+            0x00, 0x00, // checksum, recomputed on make
+            0x00, 0x00, // urgent pointer
+            0x05, 0x08, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // SACK (kind 5), unmodeled
+        ];
+        let mut header = *TcpHeader::parse(&raw).unwrap();
+        assert_eq!(
+            header.options()[0],
+            TcpOption::Unknown { kind: 5, data: vec![1, 2, 3, 4, 5, 6] }
+        );
+
+        header.set_pseudo_header(SRC_IP, DST_IP, 0);
+        let packet = header.make();
+
+        assert_eq!(&packet[0..16], &raw[0..16]);
+        assert_eq!(&packet[18..28], &raw[18..28]);
+    }
 }
\ No newline at end of file